@@ -15,6 +15,9 @@ mod msr {
     pub const FLEX_RATIO: u64 = 0x194;
     pub const FLEX_RATIO_OC_LOCK: u64 = 1 << 20;
 
+    pub const ISST_IF: u64 = 0xb0;
+    pub const ISST_DATA: u64 = 0xb1;
+
     pub struct Msr {
         dev: File,
         num: u64,
@@ -48,9 +51,43 @@ mod msr {
     }
 }
 
+mod mailbox {
+    use std::io;
+
+    use crate::msr::Msr;
+
+    // Both the OC mailbox and the ISST mailbox expose a busy/run bit in a
+    // status register that the caller must poll until it clears; neither is
+    // guaranteed to clear promptly, so the wait is bounded by a retry count
+    // rather than looping forever.
+    pub trait Mailbox {
+        /// Register carrying the busy/run bit to poll.
+        fn status_msr(&self) -> &Msr;
+        /// Mask of the busy/run bit within `status_msr`.
+        fn busy_bit(&self) -> u64;
+        /// Maximum number of polls before giving up.
+        fn retry_count(&self) -> u32;
+
+        /// Poll `status_msr` until the busy bit clears, returning its final
+        /// value, or an `ErrorKind::TimedOut` error after `retry_count`
+        /// attempts.
+        fn wait_not_busy(&self) -> io::Result<u64> {
+            for _ in 0..self.retry_count() {
+                let val = self.status_msr().read()?;
+                if val & self.busy_bit() == 0 {
+                    return Ok(val);
+                }
+            }
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Mailbox busy"))
+        }
+    }
+}
+
 mod oc_mbox {
+    use std::fmt;
     use std::io::{self, Result};
 
+    use crate::mailbox::Mailbox;
     use crate::msr::{self, Msr};
 
     #[repr(u8)]
@@ -63,39 +100,55 @@ mod oc_mbox {
         SystemAgent,
     }
 
+    impl fmt::Display for Domain {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let name = match self {
+                Domain::IaCore => "IaCore",
+                Domain::GtSlices => "GtSlices",
+                Domain::CboLlcRing => "CboLlcRing",
+                Domain::GtUnslice => "GtUnslice",
+                Domain::SystemAgent => "SystemAgent",
+            };
+            f.write_str(name)
+        }
+    }
+
+
+    // MSR access latency alone usually exceeds how long a command takes to
+    // complete, so a handful of retries is enough on bare metal; raise
+    // `retry_count` on slower/virtualized systems.
+    const DEFAULT_RETRY_COUNT: u32 = 3;
 
     pub struct OcMbox {
-        msr: Msr
+        cpu: usize,
+        msr: Msr,
+        pub retry_count: u32,
     }
 
     impl OcMbox {
         pub fn with_cpu(cpu: usize) -> Result<Self> {
             Ok(OcMbox {
-                msr: Msr::with_cpu(cpu, msr::OC_MBOX)?
+                cpu,
+                msr: Msr::with_cpu(cpu, msr::OC_MBOX)?,
+                retry_count: DEFAULT_RETRY_COUNT,
             })
         }
 
         fn poll_result(&self) -> Result<Result<u32>> {
-            loop {
-                let val = self.msr.read()?;
-                let r = val >> 63;
-                let c = (val >> 32) as u8;
-                let d = val as u32;
-                if r == 0 {
-                    let errinfo = match c {
-                        0 => return Ok(Ok(d)),
-                        1 => &"Overclocking is locked" as &dyn std::fmt::Display,
-                        0x1f => &"Unrecognized command" as _,
-                        _ => &c as _
-                    };
-                    return Ok(Err(io::Error::new(io::ErrorKind::Other, format!("Mailbox returned error: {}", errinfo))))
-                }
-            }
+            let val = self.wait_not_busy()?;
+            let c = (val >> 32) as u8;
+            let d = val as u32;
+            let errinfo = match c {
+                0 => return Ok(Ok(d)),
+                1 => &"Overclocking is locked" as &dyn std::fmt::Display,
+                0x1f => &"Unrecognized command" as _,
+                _ => &c as _
+            };
+            Ok(Err(io::Error::new(io::ErrorKind::Other, format!("Mailbox returned error: {}", errinfo))))
         }
 
         pub fn cmd(&self, command: u8, param1: u8, param2: u8, data: u32) -> Result<u32> {
             // wait until mailbox is available
-            // WARNING: racy
             let _ = self.poll_result()?;
 
             // send mailbox command
@@ -109,27 +162,411 @@ mod oc_mbox {
             // wait for mailbox completion
             self.poll_result()?
         }
+
+        pub fn read_vf_override(&self, domain: Domain) -> Result<VfOverride> {
+            let raw = self.cmd(CMD_VF_OVERRIDE_READ, domain as u8, 0, 0)?;
+            Ok(VfOverride::from_raw(raw))
+        }
+
+        pub fn write_vf_override(&self, domain: Domain, vf: VfOverride) -> Result<()> {
+            let flex_ratio = Msr::with_cpu(self.cpu, msr::FLEX_RATIO)?.read()?;
+            if flex_ratio & msr::FLEX_RATIO_OC_LOCK != 0 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "overclocking is locked (FLEX_RATIO_OC_LOCK set)"))
+            }
+
+            self.cmd(CMD_VF_OVERRIDE_WRITE, domain as u8, 0, vf.to_raw())?;
+            Ok(())
+        }
+    }
+
+    impl Mailbox for OcMbox {
+        fn status_msr(&self) -> &Msr {
+            &self.msr
+        }
+
+        fn busy_bit(&self) -> u64 {
+            1 << 63
+        }
+
+        fn retry_count(&self) -> u32 {
+            self.retry_count
+        }
     }
 
     pub const CMD_VF_OVERRIDE_READ: u8 = 0x10;
+    pub const CMD_VF_OVERRIDE_WRITE: u8 = 0x11;
+
+    // Layout of the 32-bit VF override data word, per the OC mailbox's
+    // documented VF-override interface: bit 31 selects fixed vs.
+    // adaptive/offset voltage mode, the signed voltage offset is an 11-bit
+    // field at the top of the word (bits 20-30) in units of 1/1.024 mV
+    // (~0.977 mV) per bit, the target ratio is an 8-bit field at bits 8-15,
+    // and bits 0-1 carry the interpolation/adaptive-voltage flags.
+    //
+    // These positions have not been verified against a read-modify-write
+    // round trip on real silicon; read a domain back after writing it and
+    // compare before trusting an override on hardware you haven't tested.
+    const VOLTAGE_OVERRIDE_BIT: u32 = 1 << 31;
+    const VOLTAGE_OFFSET_SHIFT: u32 = 20;
+    const VOLTAGE_OFFSET_BITS: u32 = 11;
+    const VOLTAGE_OFFSET_MASK: u32 = (1 << VOLTAGE_OFFSET_BITS) - 1;
+    const TARGET_RATIO_SHIFT: u32 = 8;
+    const INTERPOLATION_BIT: u32 = 1 << 1;
+    const ADAPTIVE_VOLTAGE_BIT: u32 = 1 << 0;
+
+    // Bits 2-7 and 16-19 aren't modeled by any field above. `VfOverride`
+    // keeps them around verbatim (see `reserved` below) so that decoding a
+    // word and re-encoding it for a write can't clobber live state we don't
+    // understand.
+    const RESERVED_MASK: u32 = !(VOLTAGE_OVERRIDE_BIT
+        | (VOLTAGE_OFFSET_MASK << VOLTAGE_OFFSET_SHIFT)
+        | (0xffu32 << TARGET_RATIO_SHIFT)
+        | INTERPOLATION_BIT
+        | ADAPTIVE_VOLTAGE_BIT);
+
+    /// Millivolts represented by one LSB of the raw voltage offset field.
+    const VOLTAGE_OFFSET_MV_PER_UNIT: f64 = 1.0 / 1.024;
+
+    fn sign_extend(raw: u32, bits: u32) -> i32 {
+        let shift = 32 - bits;
+        ((raw << shift) as i32) >> shift
+    }
+
+    /// Per-domain voltage/frequency override, encoded/decoded as the 32-bit
+    /// data word used by `CMD_VF_OVERRIDE_READ` and `CMD_VF_OVERRIDE_WRITE`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct VfOverride {
+        /// Fixed voltage override, as opposed to an adaptive/offset mode.
+        pub voltage_override: bool,
+        /// Target core ratio (multiplier) for this domain.
+        pub target_ratio: u8,
+        /// Signed voltage offset, in raw units of 1/1.024 mV (~0.977 mV)
+        /// each; see `voltage_offset_mv` for the value converted to mV.
+        pub voltage_offset_units: i32,
+        /// Enable interpolation between VF points.
+        pub interpolation_enabled: bool,
+        /// Enable adaptive voltage for this domain.
+        pub adaptive_voltage: bool,
+        /// Bits 2-7 and 16-19 of the raw word, which this driver doesn't
+        /// decode. Carried over verbatim from the word this `VfOverride`
+        /// was decoded from, so `to_raw` reproduces them unchanged instead
+        /// of zeroing them out on a write.
+        reserved: u32,
+    }
+
+    impl VfOverride {
+        pub fn from_raw(raw: u32) -> Self {
+            let offset_field = (raw >> VOLTAGE_OFFSET_SHIFT) & VOLTAGE_OFFSET_MASK;
+            VfOverride {
+                voltage_override: raw & VOLTAGE_OVERRIDE_BIT != 0,
+                target_ratio: (raw >> TARGET_RATIO_SHIFT) as u8,
+                voltage_offset_units: sign_extend(offset_field, VOLTAGE_OFFSET_BITS),
+                interpolation_enabled: raw & INTERPOLATION_BIT != 0,
+                adaptive_voltage: raw & ADAPTIVE_VOLTAGE_BIT != 0,
+                reserved: raw & RESERVED_MASK,
+            }
+        }
+
+        pub fn to_raw(&self) -> u32 {
+            let mut raw = self.reserved & RESERVED_MASK;
+            if self.voltage_override {
+                raw |= VOLTAGE_OVERRIDE_BIT;
+            }
+            raw |= ((self.voltage_offset_units as u32) & VOLTAGE_OFFSET_MASK) << VOLTAGE_OFFSET_SHIFT;
+            raw |= (self.target_ratio as u32) << TARGET_RATIO_SHIFT;
+            if self.interpolation_enabled {
+                raw |= INTERPOLATION_BIT;
+            }
+            if self.adaptive_voltage {
+                raw |= ADAPTIVE_VOLTAGE_BIT;
+            }
+            raw
+        }
+
+        /// The signed voltage offset converted to millivolts.
+        pub fn voltage_offset_mv(&self) -> f64 {
+            self.voltage_offset_units as f64 * VOLTAGE_OFFSET_MV_PER_UNIT
+        }
+    }
+
+    /// Read-only, human-readable view of a decoded VF override word.
+    /// Separate from `VfOverride` because the write path needs the
+    /// `reserved` bits preserved for a faithful round trip, while this type
+    /// only carries what's worth printing.
+    #[derive(Clone, Copy, Debug)]
+    pub struct VfOverrideInfo {
+        pub target_ratio: u8,
+        pub voltage_override: bool,
+        pub voltage_offset_mv: f64,
+        pub interpolation_enabled: bool,
+        pub adaptive_voltage: bool,
+    }
+
+    impl VfOverrideInfo {
+        pub fn from_raw(raw: u32) -> Self {
+            let vf = VfOverride::from_raw(raw);
+            VfOverrideInfo {
+                target_ratio: vf.target_ratio,
+                voltage_override: vf.voltage_override,
+                voltage_offset_mv: vf.voltage_offset_mv(),
+                interpolation_enabled: vf.interpolation_enabled,
+                adaptive_voltage: vf.adaptive_voltage,
+            }
+        }
+    }
+
+    impl From<VfOverride> for VfOverrideInfo {
+        fn from(vf: VfOverride) -> Self {
+            VfOverrideInfo::from_raw(vf.to_raw())
+        }
+    }
+
+    impl fmt::Display for VfOverrideInfo {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "ratio={} mode={} voltage={:.0}mV",
+                self.target_ratio,
+                if self.voltage_override { "fixed" } else { "offset" },
+                self.voltage_offset_mv)?;
+            if self.interpolation_enabled {
+                write!(f, " interpolation")?;
+            }
+            if self.adaptive_voltage {
+                write!(f, " adaptive")?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn vf_override_round_trips_through_raw() {
+            let vf = VfOverride {
+                voltage_override: true,
+                target_ratio: 40,
+                voltage_offset_units: -50,
+                interpolation_enabled: true,
+                adaptive_voltage: false,
+                reserved: 0,
+            };
+            assert_eq!(VfOverride::from_raw(vf.to_raw()), vf);
+        }
+
+        #[test]
+        fn voltage_offset_sign_extends_at_field_boundaries() {
+            let min = VfOverride { voltage_offset_units: -1024, ..VfOverride::from_raw(0) };
+            let max = VfOverride { voltage_offset_units: 1023, ..VfOverride::from_raw(0) };
+            assert_eq!(VfOverride::from_raw(min.to_raw()).voltage_offset_units, -1024);
+            assert_eq!(VfOverride::from_raw(max.to_raw()).voltage_offset_units, 1023);
+        }
+
+        #[test]
+        fn to_raw_preserves_unmodeled_reserved_bits() {
+            // Bits 2-7 and 16-19 are the unmodeled reserved bits; confirm a
+            // decode/encode round trip doesn't clobber them to zero.
+            let raw = 0x0055_a5a4u32 | VOLTAGE_OVERRIDE_BIT;
+            assert_eq!(VfOverride::from_raw(raw).to_raw(), raw);
+        }
+
+        #[test]
+        fn vf_override_info_matches_the_source_vf_override() {
+            let vf = VfOverride::from_raw(0x8012_3405);
+            let info = VfOverrideInfo::from(vf);
+            assert_eq!(info.target_ratio, vf.target_ratio);
+            assert_eq!(info.voltage_override, vf.voltage_override);
+            assert_eq!(info.voltage_offset_mv, vf.voltage_offset_mv());
+            assert_eq!(VfOverrideInfo::from_raw(0x8012_3405).voltage_offset_mv, info.voltage_offset_mv);
+        }
+    }
+}
+
+mod isst_mbox {
+    use std::io::{self, Result};
+
+    use crate::mailbox::Mailbox;
+    use crate::msr::{self, Msr};
+
+    const DEFAULT_RETRY_COUNT: u32 = 3;
+
+    /// Mailbox over the two-register Intel Speed Select (ISST) MSR
+    /// interface: an interface/command register (`ISST_IF`) and a data
+    /// register (`ISST_DATA`), as opposed to the OC mailbox's single
+    /// combined register.
+    pub struct IsstMbox {
+        iface: Msr,
+        data: Msr,
+        pub retry_count: u32,
+    }
+
+    impl IsstMbox {
+        pub fn with_cpu(cpu: usize) -> Result<Self> {
+            Ok(IsstMbox {
+                iface: Msr::with_cpu(cpu, msr::ISST_IF)?,
+                data: Msr::with_cpu(cpu, msr::ISST_DATA)?,
+                retry_count: DEFAULT_RETRY_COUNT,
+            })
+        }
+
+        pub fn cmd(&self, command: u8, sub_command: u8, parameter: u16, command_data: u32) -> Result<u32> {
+            // wait until mailbox is available
+            self.wait_not_busy()?;
+
+            // send mailbox command
+            self.data.write(command_data as u64)?;
+            self.iface.write(iface_msg(command, sub_command, parameter))?;
+
+            // wait for mailbox completion
+            let status = self.wait_not_busy()?;
+            if status & 0xff != 0 {
+                // ENXIO: no such mailbox command/sub-command on this part.
+                return Err(io::Error::from_raw_os_error(ENXIO))
+            }
+
+            Ok(self.data.read()? as u32)
+        }
+
+        /// `CONFIG_TDP_GET_LEVELS_INFO`: report the number of supported
+        /// config-TDP levels and the currently active one.
+        pub fn config_tdp_levels(&self) -> Result<u32> {
+            self.cmd(CMD_CONFIG_TDP, CONFIG_TDP_GET_LEVELS_INFO, 0, 0)
+        }
+    }
+
+    // errno.h ENXIO, without pulling in the libc crate for one constant.
+    const ENXIO: i32 = 6;
+
+    fn iface_msg(command: u8, sub_command: u8, parameter: u16) -> u64 {
+        (1u64 << 31) |
+            (((parameter & 0x3fff) as u64) << 16) |
+            ((sub_command as u64) << 8) |
+            (command as u64)
+    }
+
+    pub const CMD_CONFIG_TDP: u8 = 0x7f;
+    pub const CONFIG_TDP_GET_LEVELS_INFO: u8 = 0x00;
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn iface_msg_encodes_run_bit_and_fields() {
+            let msg = iface_msg(0x7f, 0x00, 0x1234);
+            assert_eq!(msg, (1u64 << 31) | (0x1234u64 << 16) | 0x7f);
+        }
+
+        #[test]
+        fn iface_msg_masks_parameter_to_14_bits() {
+            let msg = iface_msg(0, 0, 0xffff);
+            assert_eq!((msg >> 16) & 0x3fff, 0x3fff);
+            assert_eq!(msg >> 30, 0b10);
+        }
+    }
+
+    impl Mailbox for IsstMbox {
+        fn status_msr(&self) -> &Msr {
+            &self.iface
+        }
+
+        fn busy_bit(&self) -> u64 {
+            1 << 31
+        }
+
+        fn retry_count(&self) -> u32 {
+            self.retry_count
+        }
+    }
+}
+
+mod topology {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::{Error, ErrorKind, Result};
+
+    /// Enumerate one representative logical CPU per physical package, by
+    /// walking `/dev/cpu/*/msr` and mapping each CPU to its package via
+    /// `/sys/devices/system/cpu/cpuN/topology/physical_package_id`. The OC
+    /// mailbox and flex-ratio state are per-package, so operating on cpu 0
+    /// alone misses every other socket/package on the system.
+    pub fn packages() -> Result<Vec<(u32, usize)>> {
+        let mut by_package: BTreeMap<u32, usize> = BTreeMap::new();
+
+        for entry in fs::read_dir("/dev/cpu")? {
+            let entry = entry?;
+            let cpu: usize = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(cpu) => cpu,
+                None => continue,
+            };
+            if !entry.path().join("msr").exists() {
+                continue;
+            }
+
+            let package_path = format!("/sys/devices/system/cpu/cpu{}/topology/physical_package_id", cpu);
+            let package: u32 = fs::read_to_string(package_path)?
+                .trim()
+                .parse()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "malformed physical_package_id"))?;
+
+            by_package.entry(package)
+                .and_modify(|representative| *representative = (*representative).min(cpu))
+                .or_insert(cpu);
+        }
+
+        Ok(by_package.into_iter().collect())
+    }
 }
 
 fn main() -> Result<()> {
+    // By default this tool only reads state. Pass --write-back to also
+    // write each VF override straight back after reading it, as a
+    // read-modify-write smoke test of the write path (including the
+    // OC-lock check); this is opt-in because it issues a real VF override
+    // write command.
+    let write_back = std::env::args().any(|arg| arg == "--write-back");
+
     let status = Command::new("modprobe").arg("msr").status()?;
     if !status.success() {
         return Err(io::Error::new(io::ErrorKind::Other, format!("modprobe exited with status: {}", status)))
     }
 
-    let flex_ratio = msr::Msr::with_cpu(0, msr::FLEX_RATIO)?.read()?;
-    println!("Overclocking lock: {}", (flex_ratio & msr::FLEX_RATIO_OC_LOCK) != 0);
-
-    let ocmbox = oc_mbox::OcMbox::with_cpu(0)?;
-    
     use oc_mbox::Domain::*;
     let domains = [IaCore, GtSlices, CboLlcRing, GtUnslice, SystemAgent];
-    for &domain in &domains {
-        println!("domain {}: {:08x}", domain as u8, ocmbox.cmd(oc_mbox::CMD_VF_OVERRIDE_READ, domain as _, 0, 0)?);
+
+    for (package, cpu) in topology::packages()? {
+        println!("package {} (cpu {}):", package, cpu);
+
+        let flex_ratio = msr::Msr::with_cpu(cpu, msr::FLEX_RATIO)?.read()?;
+        println!("  Overclocking lock: {}", (flex_ratio & msr::FLEX_RATIO_OC_LOCK) != 0);
+
+        let ocmbox = oc_mbox::OcMbox::with_cpu(cpu)?;
+        for &domain in &domains {
+            let vf = ocmbox.read_vf_override(domain)?;
+            println!("  {}: {}", domain, oc_mbox::VfOverrideInfo::from(vf));
+
+            if write_back {
+                // Write the value straight back: a no-op in effect, but it
+                // exercises the write path (and the OC-lock check) without
+                // actually changing the override in place.
+                match ocmbox.write_vf_override(domain, vf) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        println!("  {}: write skipped: {}", domain, e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        // Best-effort: most parts (and many Xeons) don't implement the
+        // Speed Select interface at all, so a failure here just means it's
+        // unsupported on this package, not that the tool should abort.
+        match isst_mbox::IsstMbox::with_cpu(cpu).and_then(|isst| isst.config_tdp_levels()) {
+            Ok(levels) => println!("  ISST config TDP levels: {:08x}", levels),
+            Err(e) => println!("  ISST probe failed (Speed Select unsupported?): {}", e),
+        }
     }
-    
+
     Ok(())
 }